@@ -7,15 +7,28 @@ use std::{cmp, iter};
 use bincode;
 use rand;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use common_subset::{self, CommonSubset};
-use messaging::{DistAlgorithm, TargetedMessage};
+use crypto::{Ciphertext, DecryptionShare, PublicKeySet, SecretKeyShare};
+use messaging::{DistAlgorithm, Target, TargetedMessage};
+
+/// How many epochs ahead of the current one this node buffers sub-algorithm traffic for. Messages
+/// for epochs further in the future are dropped: the membership of those epochs is not settled yet,
+/// and a correct node never runs more than a bounded number of epochs ahead of us.
+const MAX_FUTURE_EPOCHS: u64 = 3;
 
 /// An instance of the Honey Badger Byzantine fault tolerant consensus algorithm.
 pub struct HoneyBadger<T, N: Eq + Hash + Ord + Clone> {
-    /// The buffer of transactions that have not yet been included in any output batch.
+    /// The de-duplicating, oldest-first buffer of transactions that have not yet been included in
+    /// any output batch.
     buffer: Vec<T>,
+    /// An index of the transactions currently in `buffer`, keeping de-duplication `O(log n)` rather
+    /// than scanning the whole buffer on every insert.
+    buffer_index: BTreeSet<T>,
+    /// The maximum number of transactions the buffer may hold. Further transactions are rejected
+    /// with `Error::BufferFull` until decided batches free up space.
+    capacity: usize,
     /// The earliest epoch from which we have not yet received output.
     epoch: u64,
     /// The Asynchronous Common Subset instance that decides which nodes' transactions to include,
@@ -25,6 +38,37 @@ pub struct HoneyBadger<T, N: Eq + Hash + Ord + Clone> {
     id: N,
     /// The set of all node IDs of the participants (including ourselves).
     all_uids: HashSet<N>,
+    /// The IDs of all current participants in a fixed order, used to order agreed ciphertexts
+    /// deterministically. This is recomputed when the membership changes.
+    ordered_uids: Vec<N>,
+    /// Each node's threshold-key share index, assigned once at genesis and never changed so that
+    /// decryption shares stay verifiable against the right key share across membership changes.
+    key_indices: BTreeMap<N, u64>,
+    /// The public key set of the common threshold encryption scheme. Proposals are encrypted under
+    /// the master public key, and decryption shares are verified against the individual key shares.
+    public_key_set: PublicKeySet,
+    /// This node's secret key share, used to produce decryption shares for agreed ciphertexts.
+    /// Observers have no share and therefore contribute no decryption shares.
+    secret_key_share: Option<SecretKeyShare>,
+    /// Whether this node is a passive observer: it follows the output stream but never proposes,
+    /// sends common subset messages or contributes decryption shares.
+    is_observer: bool,
+    /// The membership change this node currently votes for, included in every proposal until it is
+    /// committed or withdrawn.
+    vote: Option<Change<N>>,
+    /// The threshold decryption state of each epoch whose common subset has already terminated but
+    /// whose ciphertexts have not yet been fully decrypted.
+    decryption_states: BTreeMap<u64, DecryptionState>,
+    /// Decryption shares received for an epoch before its own common subset has terminated, keyed by
+    /// epoch. They are applied once the epoch's `DecryptionState` is created, so a lagging node does
+    /// not permanently miss honest shares that were broadcast only once. Bounded to a small window of
+    /// future epochs and a per-epoch cap so a faulty peer cannot make us buffer without limit.
+    pending_shares: BTreeMap<u64, Vec<PendingShare>>,
+    /// Common subset messages received for a future epoch, before its membership is settled and its
+    /// `CommonSubset` instance exists. They are replayed once this node reaches that epoch. Bounded
+    /// to a small window of future epochs and a per-epoch cap so a faulty peer cannot make us buffer
+    /// without limit.
+    pending_cs_messages: BTreeMap<u64, Vec<(N, common_subset::Message<N>)>>,
     /// The target number of transactions to be included in each batch.
     // TODO: Do experiments and recommend a batch size. It should be proportional to
     // `num_nodes * num_nodes * log(num_nodes)`.
@@ -32,17 +76,17 @@ pub struct HoneyBadger<T, N: Eq + Hash + Ord + Clone> {
     /// The messages that need to be sent to other nodes.
     messages: VecDeque<TargetedMessage<Message<N>, N>>,
     /// The outputs from completed epochs.
-    output: VecDeque<Batch<T>>,
+    output: VecDeque<Batch<T, N>>,
 }
 
 impl<T, N> DistAlgorithm for HoneyBadger<T, N>
 where
-    T: Ord + Serialize + DeserializeOwned + Debug,
-    N: Eq + Hash + Ord + Clone + Debug,
+    T: Clone + Ord + Serialize + DeserializeOwned + Debug,
+    N: Eq + Hash + Ord + Clone + Serialize + DeserializeOwned + Debug,
 {
     type NodeUid = N;
     type Input = T;
-    type Output = Batch<T>;
+    type Output = Batch<T, N>;
     type Message = Message<N>;
     type Error = Error;
 
@@ -58,6 +102,9 @@ where
             Message::CommonSubset(epoch, cs_msg) => {
                 self.handle_common_subset_message(sender_id, epoch, cs_msg)
             }
+            Message::DecryptionShare(epoch, ciphertext_index, share) => {
+                self.handle_decryption_share(sender_id, epoch, ciphertext_index, share)
+            }
         }
     }
 
@@ -78,14 +125,25 @@ where
     }
 }
 
-// TODO: Use a threshold encryption scheme to encrypt the proposed transactions.
 impl<T, N> HoneyBadger<T, N>
 where
-    T: Ord + Serialize + DeserializeOwned + Debug,
-    N: Eq + Hash + Ord + Clone + Debug,
+    T: Clone + Ord + Serialize + DeserializeOwned + Debug,
+    N: Eq + Hash + Ord + Clone + Serialize + DeserializeOwned + Debug,
 {
     /// Returns a new Honey Badger instance with the given parameters, starting at epoch `0`.
-    pub fn new<I, TI>(id: N, all_uids_iter: I, batch_size: usize, txs: TI) -> Result<Self, Error>
+    ///
+    /// `public_key_set` is the common public key set of the threshold encryption scheme under which
+    /// proposals are encrypted, and `secret_key_share` is this node's share of the corresponding
+    /// secret key, used to produce decryption shares.
+    pub fn new<I, TI>(
+        id: N,
+        all_uids_iter: I,
+        public_key_set: PublicKeySet,
+        secret_key_share: SecretKeyShare,
+        batch_size: usize,
+        capacity: usize,
+        txs: TI,
+    ) -> Result<Self, Error>
     where
         I: IntoIterator<Item = N>,
         TI: IntoIterator<Item = T>,
@@ -94,26 +152,225 @@ where
         if !all_uids.contains(&id) {
             return Err(Error::OwnIdMissing);
         }
+        let mut ordered_uids: Vec<N> = all_uids.iter().cloned().collect();
+        ordered_uids.sort();
+        let key_indices = ordered_uids
+            .iter()
+            .enumerate()
+            .map(|(i, uid)| (uid.clone(), i as u64))
+            .collect();
         let mut honey_badger = HoneyBadger {
-            buffer: txs.into_iter().collect(),
+            buffer: Vec::new(),
+            buffer_index: BTreeSet::new(),
+            capacity,
             epoch: 0,
             common_subsets: BTreeMap::new(),
             id,
+            all_uids,
+            ordered_uids,
+            key_indices,
+            public_key_set,
+            secret_key_share: Some(secret_key_share),
+            is_observer: false,
+            vote: None,
+            decryption_states: BTreeMap::new(),
+            pending_shares: BTreeMap::new(),
+            pending_cs_messages: BTreeMap::new(),
+            batch_size,
+            messages: VecDeque::new(),
+            output: VecDeque::new(),
+        };
+        honey_badger.add_transactions(txs)?;
+        honey_badger.propose()?;
+        Ok(honey_badger)
+    }
+
+    /// Returns a new Honey Badger instance that joins an ongoing run at `start_epoch`, bootstrapping
+    /// from the membership it was added to.
+    ///
+    /// Because a joining node was not part of the common subset of earlier epochs, it starts
+    /// proposing only from `start_epoch`, which is the epoch boundary at which the `Change::Add` that
+    /// admitted it takes effect on every correct node.
+    pub fn new_joining<I, TI>(
+        id: N,
+        all_uids_iter: I,
+        public_key_set: PublicKeySet,
+        secret_key_share: SecretKeyShare,
+        batch_size: usize,
+        capacity: usize,
+        txs: TI,
+        start_epoch: u64,
+    ) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = N>,
+        TI: IntoIterator<Item = T>,
+    {
+        let mut honey_badger = HoneyBadger::new(
+            id,
+            all_uids_iter,
+            public_key_set,
+            secret_key_share,
             batch_size,
+            capacity,
+            txs,
+        )?;
+        // Discard the genesis-epoch proposal and resume from where the rest of the network stands.
+        honey_badger.common_subsets.clear();
+        honey_badger.messages.clear();
+        honey_badger.epoch = start_epoch;
+        honey_badger.propose()?;
+        Ok(honey_badger)
+    }
+
+    /// Returns a new observer instance that follows the output stream without participating in
+    /// consensus.
+    ///
+    /// An observer does not appear in `all_uids`, never proposes or sends common subset messages and
+    /// holds no secret key share, but it ingests the broadcast traffic it receives and reconstructs
+    /// each epoch's `Batch` from the decryption shares of the validators. This lets light clients and
+    /// auditors track the agreed transaction log.
+    pub fn new_observer<I>(
+        id: N,
+        all_uids_iter: I,
+        public_key_set: PublicKeySet,
+        batch_size: usize,
+    ) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = N>,
+    {
+        let all_uids: HashSet<N> = all_uids_iter.into_iter().collect();
+        let mut ordered_uids: Vec<N> = all_uids.iter().cloned().collect();
+        ordered_uids.sort();
+        let key_indices = ordered_uids
+            .iter()
+            .enumerate()
+            .map(|(i, uid)| (uid.clone(), i as u64))
+            .collect();
+        Ok(HoneyBadger {
+            buffer: Vec::new(),
+            buffer_index: BTreeSet::new(),
+            capacity: 0,
+            epoch: 0,
+            common_subsets: BTreeMap::new(),
+            id,
             all_uids,
+            ordered_uids,
+            key_indices,
+            public_key_set,
+            secret_key_share: None,
+            is_observer: true,
+            vote: None,
+            decryption_states: BTreeMap::new(),
+            pending_shares: BTreeMap::new(),
+            pending_cs_messages: BTreeMap::new(),
+            batch_size,
             messages: VecDeque::new(),
             output: VecDeque::new(),
+        })
+    }
+
+    /// Returns a serializable snapshot of this instance, suitable for checkpointing to disk.
+    ///
+    /// The snapshot captures the current `epoch`, the pending transaction buffer, the configuration
+    /// (batch size and capacity), the current membership and change vote, and the batches that have
+    /// already been output but not yet consumed by the caller. In-flight `common_subsets` and
+    /// decryption shares are deliberately _not_ captured: those epochs have not produced output yet,
+    /// so discarding them is safe — the restarted node simply re-proposes in `epoch` and reaches
+    /// agreement again with the rest of the network.
+    pub fn snapshot(&self) -> Snapshot<T, N>
+    where
+        T: Clone,
+    {
+        Snapshot {
+            epoch: self.epoch,
+            buffer: self.buffer.clone(),
+            capacity: self.capacity,
+            batch_size: self.batch_size,
+            all_uids: self.all_uids.clone(),
+            key_indices: self.key_indices.clone(),
+            vote: self.vote.clone(),
+            outputs: self.output.iter().cloned().collect(),
+        }
+    }
+
+    /// Restores a Honey Badger instance from a snapshot, resuming at the snapshot's epoch.
+    ///
+    /// The threshold keys are supplied by the operator rather than stored in the snapshot. The
+    /// restored node keeps its pending buffer and not-yet-consumed output batches, and immediately
+    /// re-proposes for the resumed epoch; any common subset progress from before the crash is
+    /// re-derived from the network.
+    ///
+    /// This restores liveness only while the snapshot epoch has not yet been decided by the rest of
+    /// the network. If the network has already moved past it, peers discard the node's messages for
+    /// that epoch as obsolete and no traffic of earlier epochs is sent to it again, so the node does
+    /// not catch up.
+    pub fn from_snapshot(
+        id: N,
+        public_key_set: PublicKeySet,
+        secret_key_share: SecretKeyShare,
+        snapshot: Snapshot<T, N>,
+    ) -> Result<Self, Error> {
+        if !snapshot.all_uids.contains(&id) {
+            return Err(Error::OwnIdMissing);
+        }
+        let mut ordered_uids: Vec<N> = snapshot.all_uids.iter().cloned().collect();
+        ordered_uids.sort();
+        let buffer_index: BTreeSet<T> = snapshot.buffer.iter().cloned().collect();
+        let mut honey_badger = HoneyBadger {
+            buffer: snapshot.buffer,
+            buffer_index,
+            capacity: snapshot.capacity,
+            epoch: snapshot.epoch,
+            common_subsets: BTreeMap::new(),
+            id,
+            all_uids: snapshot.all_uids,
+            ordered_uids,
+            key_indices: snapshot.key_indices,
+            public_key_set,
+            secret_key_share: Some(secret_key_share),
+            is_observer: false,
+            vote: snapshot.vote,
+            decryption_states: BTreeMap::new(),
+            pending_shares: BTreeMap::new(),
+            pending_cs_messages: BTreeMap::new(),
+            batch_size: snapshot.batch_size,
+            messages: VecDeque::new(),
+            output: snapshot.outputs.into_iter().collect(),
         };
         honey_badger.propose()?;
         Ok(honey_badger)
     }
 
-    /// Adds transactions into the buffer.
+    /// Adds transactions into the buffer, skipping any that are already buffered.
+    ///
+    /// Transactions are appended in order, so the buffer stays oldest-first. If adding a transaction
+    /// would exceed the configured capacity, it is rejected with `Error::BufferFull` and the
+    /// remaining transactions are not added.
     pub fn add_transactions<I: IntoIterator<Item = T>>(&mut self, txs: I) -> Result<(), Error> {
-        self.buffer.extend(txs);
+        if self.is_observer {
+            // Observers do not propose, so buffering input would serve no purpose. Silently ignore
+            // it rather than filling a zero-capacity buffer and returning `Error::BufferFull`.
+            return Ok(());
+        }
+        for tx in txs {
+            if self.buffer_index.contains(&tx) {
+                continue; // Deduplicate against transactions still awaiting inclusion.
+            }
+            if self.buffer.len() >= self.capacity {
+                return Err(Error::BufferFull);
+            }
+            self.buffer_index.insert(tx.clone());
+            self.buffer.push(tx);
+        }
         Ok(())
     }
 
+    /// Votes for a change to the validator set. The vote is attached to every subsequent proposal
+    /// until the change is committed, and takes effect once a decided batch carries it.
+    pub fn vote_for(&mut self, change: Change<N>) {
+        self.vote = Some(change);
+    }
+
     /// Proposes a new batch in the current epoch.
     fn propose(&mut self) -> Result<(), Error> {
         let proposal = self.choose_transactions()?;
@@ -132,21 +389,48 @@ where
         Ok(())
     }
 
-    /// Returns a random choice of `batch_size / all_uids.len()` buffered transactions, and
-    /// serializes them.
+    /// Returns the transactions picked by `select_transactions` (at most
+    /// `max(1, batch_size / all_uids.len())` of them), serialized together with this node's change
+    /// vote and encrypted under the common public key.
+    ///
+    /// The common subset agrees on ciphertexts, so a Byzantine scheduler cannot read the contents
+    /// of any proposal before agreement is final, which makes targeted censorship infeasible.
     fn choose_transactions(&self) -> Result<Vec<u8>, Error> {
-        let mut rng = rand::thread_rng();
-        let amount = cmp::max(1, self.batch_size / self.all_uids.len());
-        let batch_size = cmp::min(self.batch_size, self.buffer.len());
-        let sample = match rand::seq::sample_iter(&mut rng, &self.buffer[..batch_size], amount) {
-            Ok(choice) => choice,
-            Err(choice) => choice, // Fewer than `amount` were available, which is fine.
-        };
+        let sample = self.select_transactions();
         debug!(
             "{:?} Proposing in epoch {}: {:?}",
             self.id, self.epoch, sample
         );
-        Ok(bincode::serialize(&sample)?)
+        // A contribution is the chosen transactions together with this node's current change vote;
+        // `try_decrypt` recovers it as a `(Vec<T>, Option<Change<N>>)` tuple.
+        let ser_batch = bincode::serialize(&(&sample, &self.vote))?;
+        let ciphertext = self.public_key_set.public_key().encrypt(&ser_batch);
+        Ok(bincode::serialize(&ciphertext)?)
+    }
+
+    /// Selects the transactions to propose in the current epoch: the oldest buffered transaction, so
+    /// nothing is starved, plus a random sample of the rest to fill the remaining slots. With room
+    /// for only one, a single transaction is sampled from the whole buffer.
+    fn select_transactions(&self) -> Vec<&T> {
+        let mut rng = rand::thread_rng();
+        let amount = cmp::max(1, self.batch_size / self.all_uids.len());
+        if self.buffer.is_empty() {
+            return Vec::new();
+        }
+        if amount == 1 {
+            return match rand::seq::sample_iter(&mut rng, &self.buffer, 1) {
+                Ok(choice) => choice,
+                Err(choice) => choice,
+            };
+        }
+        let (oldest, rest) = self.buffer.split_first().expect("buffer is non-empty");
+        let mut sample = vec![oldest];
+        let extra = match rand::seq::sample_iter(&mut rng, rest, amount - 1) {
+            Ok(choice) => choice,
+            Err(choice) => choice, // Fewer than `amount - 1` were available, which is fine.
+        };
+        sample.extend(extra);
+        sample
     }
 
     /// Handles a message for the common subset sub-algorithm.
@@ -156,66 +440,312 @@ where
         epoch: u64,
         message: common_subset::Message<N>,
     ) -> Result<(), Error> {
-        {
-            // Borrow the instance for `epoch`, or create it.
-            let cs = match self.common_subsets.entry(epoch) {
-                Entry::Occupied(entry) => entry.into_mut(),
-                Entry::Vacant(entry) => {
-                    if epoch < self.epoch {
-                        return Ok(()); // Epoch has already terminated. Message is obsolete.
-                    } else {
-                        entry.insert(CommonSubset::new(self.id.clone(), &self.all_uids)?)
+        if epoch < self.epoch {
+            return Ok(()); // Epoch has already terminated. Message is obsolete.
+        }
+        if !self.common_subsets.contains_key(&epoch) {
+            // We have not yet reached this epoch, so its membership is not settled and we must not
+            // create a `CommonSubset` from the current — possibly stale — node set. Buffer the
+            // message within the future-epoch window and replay it once we reach this epoch.
+            if epoch > self.epoch {
+                if epoch <= self.epoch + MAX_FUTURE_EPOCHS {
+                    let buffered = self
+                        .pending_cs_messages
+                        .entry(epoch)
+                        .or_insert_with(Vec::new);
+                    if buffered.len() < self.all_uids.len() * self.all_uids.len() {
+                        buffered.push((sender_id.clone(), message));
                     }
                 }
-            };
-            // Handle the message and put the outgoing messages into the queue.
-            cs.handle_message(sender_id, message)?;
+                return Ok(());
+            }
+            // The current epoch's membership is settled, so it is safe to instantiate here even if we
+            // have not proposed yet (for example as an observer, which never proposes).
+            let cs = CommonSubset::new(self.id.clone(), &self.all_uids)?;
+            self.common_subsets.insert(epoch, cs);
+        }
+        self.deliver_common_subset_message(sender_id, epoch, message)?;
+        // If this is the current epoch, the message could cause a new output.
+        if epoch == self.epoch {
+            self.process_output()?;
+        }
+        self.remove_terminated(epoch);
+        Ok(())
+    }
+
+    /// Delivers a common subset message to the (already existing) instance for `epoch` and queues any
+    /// resulting outgoing messages, unless this node is a passive observer.
+    fn deliver_common_subset_message(
+        &mut self,
+        sender_id: &N,
+        epoch: u64,
+        message: common_subset::Message<N>,
+    ) -> Result<(), Error> {
+        let cs = self
+            .common_subsets
+            .get_mut(&epoch)
+            .expect("common subset instance exists");
+        cs.handle_message(sender_id, message)?;
+        if !self.is_observer {
             for targeted_msg in cs.message_iter() {
                 let msg = targeted_msg.map(|cs_msg| Message::CommonSubset(epoch, cs_msg));
                 self.messages.push_back(msg);
             }
         }
-        // If this is the current epoch, the message could cause a new output.
+        Ok(())
+    }
+
+    /// Instantiates the current epoch's common subset (its membership is now settled) and delivers
+    /// any messages that were buffered for it while it was still a future epoch.
+    fn replay_pending_cs_messages(&mut self) -> Result<(), Error> {
+        let epoch = self.epoch;
+        let buffered = match self.pending_cs_messages.remove(&epoch) {
+            Some(buffered) => buffered,
+            None => return Ok(()),
+        };
+        if !self.common_subsets.contains_key(&epoch) {
+            let cs = CommonSubset::new(self.id.clone(), &self.all_uids)?;
+            self.common_subsets.insert(epoch, cs);
+        }
+        for (sender_id, message) in buffered {
+            self.deliver_common_subset_message(&sender_id, epoch, message)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a decryption share received from another node for a ciphertext of the given epoch.
+    fn handle_decryption_share(
+        &mut self,
+        sender_id: &N,
+        epoch: u64,
+        ciphertext_index: usize,
+        share: DecryptionShare,
+    ) -> Result<(), Error> {
+        if epoch < self.epoch {
+            return Ok(()); // Epoch has already been decrypted. Share is obsolete.
+        }
+        let sender_index = match self.node_index(sender_id) {
+            Some(index) => index,
+            None => return Err(Error::UnknownSender),
+        };
+        let pending = PendingShare {
+            sender_index,
+            ciphertext_index,
+            share,
+        };
+        if self.decryption_states.contains_key(&epoch) {
+            // The ciphertexts are already known, so verify and store the share right away.
+            self.add_share(epoch, pending);
+        } else if epoch <= self.epoch + MAX_FUTURE_EPOCHS {
+            // Our own common subset for this epoch has not terminated yet. Decryption shares are
+            // broadcast only once, so buffer this one and apply it when the `DecryptionState` is
+            // created, instead of dropping it. A correct node sends at most one share per ciphertext
+            // and there is one ciphertext per participant, so anything beyond that is a faulty peer
+            // trying to make us buffer without limit; cap the queue and drop the excess.
+            let shares = self.pending_shares.entry(epoch).or_insert_with(Vec::new);
+            if shares.len() < self.all_uids.len() * self.all_uids.len() {
+                shares.push(pending);
+            }
+        }
         if epoch == self.epoch {
             self.process_output()?;
         }
-        self.remove_terminated(epoch);
         Ok(())
     }
 
-    /// Checks whether the current epoch has output, and if it does, advances the epoch and
-    /// proposes a new batch.
+    /// Verifies a decryption share against the agreed ciphertext and, if valid, records it in the
+    /// epoch's `DecryptionState`. Invalid or out-of-range shares are dropped.
+    fn add_share(&mut self, epoch: u64, pending: PendingShare) {
+        let pk_share = self.public_key_set.public_key_share(pending.sender_index);
+        if let Some(state) = self.decryption_states.get_mut(&epoch) {
+            let ciphertext = match state.ciphertexts.get(pending.ciphertext_index) {
+                Some(ciphertext) => ciphertext,
+                None => return,
+            };
+            if !pk_share.verify_decryption_share(&pending.share, ciphertext) {
+                debug!(
+                    "{:?} Invalid decryption share for epoch {}.",
+                    self.id, epoch
+                );
+                return;
+            }
+            state.shares[pending.ciphertext_index].insert(pending.sender_index, pending.share);
+        }
+    }
+
+    /// Checks whether the current epoch can make progress, decrypting agreed ciphertexts and, once
+    /// the full batch is recovered, advancing the epoch and proposing a new batch.
     fn process_output(&mut self) -> Result<(), Error> {
         let old_epoch = self.epoch;
-        while let Some(ser_batches) = self.take_current_output() {
-            // Deserialize the output.
-            let transactions: BTreeSet<T> = ser_batches
-                .into_iter()
-                .map(|(_, ser_batch)| bincode::deserialize::<Vec<T>>(&ser_batch))
-                .collect::<Result<Vec<Vec<T>>, _>>()?
-                .into_iter()
-                .flat_map(|txs| txs)
-                .collect();
-            // Remove the output transactions from our buffer.
-            self.buffer.retain(|tx| !transactions.contains(tx));
-            debug!(
-                "{:?} Epoch {} output {:?}",
-                self.id, self.epoch, transactions
-            );
-            // Queue the output and advance the epoch.
-            self.output.push_back(Batch {
-                epoch: self.epoch,
-                transactions,
-            });
-            self.epoch += 1;
-        }
-        // If we have moved to a new epoch, propose a new batch of transactions.
-        if self.epoch > old_epoch {
+        loop {
+            // Once the common subset has terminated, record the agreed ciphertexts and broadcast our
+            // own decryption shares.
+            if !self.decryption_states.contains_key(&self.epoch) {
+                match self.take_current_output() {
+                    Some(ser_ciphertexts) => self.start_decryption(ser_ciphertexts)?,
+                    None => break,
+                }
+            }
+            // Try to recover the plaintext batch from the shares collected so far.
+            match self.try_decrypt()? {
+                Some((transactions, change)) => {
+                    self.advance_epoch(transactions, change);
+                    // The new epoch's membership is now settled, so it is safe to instantiate its
+                    // common subset and replay any of its traffic that arrived early. A fully
+                    // buffered epoch can then make progress on the next loop iteration.
+                    self.replay_pending_cs_messages()?;
+                }
+                None => break,
+            }
+        }
+        // If we have moved to a new epoch, propose a new batch of transactions. Observers never
+        // propose.
+        if self.epoch > old_epoch && !self.is_observer {
             self.propose()?;
         }
         Ok(())
     }
 
+    /// Records the agreed ciphertexts of the current epoch and broadcasts this node's decryption
+    /// share for each of them.
+    fn start_decryption(&mut self, ser_ciphertexts: HashMap<N, Vec<u8>>) -> Result<(), Error> {
+        // Deserialize the agreed ciphertexts into a deterministic order, so every node addresses
+        // them by the same index.
+        let mut ciphertexts: Vec<Ciphertext> = Vec::new();
+        for uid in &self.ordered_uids {
+            if let Some(ser_ciphertext) = ser_ciphertexts.get(uid) {
+                ciphertexts.push(bincode::deserialize(ser_ciphertext)?);
+            }
+        }
+        let shares = vec![BTreeMap::new(); ciphertexts.len()];
+        let epoch = self.epoch;
+        if let Some(ref secret_key_share) = self.secret_key_share {
+            for (ciphertext_index, ciphertext) in ciphertexts.iter().enumerate() {
+                // A malformed ciphertext was proposed by a faulty node. It is deterministically
+                // detectable, so every correct node skips it instead of contributing a share; see
+                // `try_decrypt`, which treats it as an empty contribution so the epoch still
+                // advances.
+                if !ciphertext.verify() {
+                    continue;
+                }
+                if let Some(share) = secret_key_share.decrypt_share(ciphertext) {
+                    self.messages.push_back(TargetedMessage {
+                        target: Target::All,
+                        message: Message::DecryptionShare(epoch, ciphertext_index, share),
+                    });
+                }
+            }
+        }
+        self.decryption_states
+            .insert(epoch, DecryptionState { ciphertexts, shares });
+        // Apply any shares that arrived before we agreed on this epoch's ciphertexts.
+        for pending in self.pending_shares.remove(&epoch).unwrap_or_default() {
+            self.add_share(epoch, pending);
+        }
+        Ok(())
+    }
+
+    /// Attempts to recover the plaintext transactions of the current epoch, together with any
+    /// membership change that more than `f` of the decided contributions voted for. Returns `None`
+    /// until every agreed ciphertext has reached `f + 1` valid decryption shares.
+    fn try_decrypt(&self) -> Result<Option<(BTreeSet<T>, Option<Change<N>>)>, Error> {
+        let state = match self.decryption_states.get(&self.epoch) {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        let threshold = self.public_key_set.threshold();
+        let mut transactions = BTreeSet::new();
+        let mut votes: BTreeMap<Change<N>, usize> = BTreeMap::new();
+        for (ciphertext, shares) in state.ciphertexts.iter().zip(&state.shares) {
+            // A malformed ciphertext can never gather valid shares from correct nodes, so it would
+            // otherwise stall the epoch forever. Treat it as an empty contribution and skip it.
+            if !ciphertext.verify() {
+                continue;
+            }
+            if shares.len() <= threshold {
+                return Ok(None); // Not yet enough shares to interpolate the plaintext.
+            }
+            let ser_batch = self
+                .public_key_set
+                .decrypt(shares.iter().map(|(i, share)| (*i, share)), ciphertext)?;
+            let (txs, vote): (Vec<T>, Option<Change<N>>) = bincode::deserialize(&ser_batch)?;
+            transactions.extend(txs);
+            if let Some(change) = vote {
+                *votes.entry(change).or_insert(0) += 1;
+            }
+        }
+        // A change is committed once more than `f` of the decided contributions voted for it, so
+        // every correct node, seeing the same agreed set, commits the same change.
+        let change = votes
+            .into_iter()
+            .find(|&(_, count)| count > threshold)
+            .map(|(change, _)| change);
+        Ok(Some((transactions, change)))
+    }
+
+    /// Removes the decided transactions from the buffer, applies any committed membership change,
+    /// queues the output batch and advances to the next epoch.
+    fn advance_epoch(&mut self, transactions: BTreeSet<T>, change: Option<Change<N>>) {
+        self.buffer.retain(|tx| !transactions.contains(tx));
+        for tx in &transactions {
+            self.buffer_index.remove(tx);
+        }
+        debug!(
+            "{:?} Epoch {} output {:?}",
+            self.id, self.epoch, transactions
+        );
+        // A change that `apply_change` ignores is not reported as committed.
+        let change = match change {
+            Some(change) => {
+                if self.apply_change(&change) {
+                    Some(change)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        self.output.push_back(Batch {
+            epoch: self.epoch,
+            transactions,
+            change,
+        });
+        self.decryption_states.remove(&self.epoch);
+        self.pending_shares.remove(&self.epoch);
+        self.pending_cs_messages.remove(&self.epoch);
+        self.epoch += 1;
+    }
+
+    /// Applies a committed membership change so future epochs use the updated node set. The genesis
+    /// threshold keys are not re-keyed, so a node can only be (re-)added if it already holds a share;
+    /// adding one that never did is rejected, as its decryption shares could never be verified.
+    /// Returns whether the change took effect.
+    fn apply_change(&mut self, change: &Change<N>) -> bool {
+        match *change {
+            Change::Add(ref uid) => {
+                if !self.key_indices.contains_key(uid) {
+                    debug!(
+                        "{:?} Ignoring Change::Add({:?}): node has no key share (re-keying \
+                         required).",
+                        self.id, uid
+                    );
+                    return false;
+                }
+                self.all_uids.insert(uid.clone());
+            }
+            Change::Remove(ref uid) => {
+                self.all_uids.remove(uid);
+            }
+        }
+        self.ordered_uids = self.all_uids.iter().cloned().collect();
+        self.ordered_uids.sort();
+        // The change has taken effect; stop voting for it.
+        if self.vote.as_ref() == Some(change) {
+            self.vote = None;
+        }
+        true
+    }
+
     /// Returns the output of the current epoch's `CommonSubset` instance, if any.
     fn take_current_output(&mut self) -> Option<HashMap<N, Vec<u8>>> {
         self.common_subsets
@@ -223,6 +753,12 @@ where
             .and_then(CommonSubset::next_output)
     }
 
+    /// Returns the threshold-key share index of the node with the given ID, as assigned at genesis.
+    /// Returns `None` for a node that was never part of the key set.
+    fn node_index(&self, id: &N) -> Option<u64> {
+        self.key_indices.get(id).cloned()
+    }
+
     /// Removes all `CommonSubset` instances from _past_ epochs that have terminated.
     fn remove_terminated(&mut self, from_epoch: u64) {
         for epoch in from_epoch..self.epoch {
@@ -237,11 +773,70 @@ where
     }
 }
 
+/// The threshold decryption state of a single epoch: the agreed ciphertexts and the decryption
+/// shares received for each of them, keyed by the sender's share index.
+struct DecryptionState {
+    /// The agreed ciphertexts, in a deterministic order shared by all nodes.
+    ciphertexts: Vec<Ciphertext>,
+    /// The decryption shares received for each ciphertext, keyed by the sending node's share index.
+    shares: Vec<BTreeMap<u64, DecryptionShare>>,
+}
+
+/// A decryption share together with the indices needed to verify and place it, buffered until the
+/// corresponding epoch's ciphertexts are known.
+struct PendingShare {
+    /// The share index of the node that sent the share.
+    sender_index: u64,
+    /// The index of the ciphertext the share decrypts.
+    ciphertext_index: usize,
+    /// The decryption share itself.
+    share: DecryptionShare,
+}
+
+/// A serializable checkpoint of a `HoneyBadger` instance, used for crash recovery.
+///
+/// It holds the safely recoverable state: the resumed epoch, the pending buffer, the configuration,
+/// the membership and change vote, and the decided output batches not yet taken by the caller.
+/// In-flight common subset and decryption state is not part of the snapshot, because those epochs
+/// had not yet produced output and are re-derived from the network after a restart.
+#[cfg_attr(feature = "serialization-serde", derive(Serialize, Deserialize))]
+#[derive(Clone)]
+pub struct Snapshot<T, N: Eq + Hash + Ord + Clone> {
+    epoch: u64,
+    buffer: Vec<T>,
+    capacity: usize,
+    batch_size: usize,
+    all_uids: HashSet<N>,
+    /// The genesis share-index assignment, preserved so that decryption shares keep verifying
+    /// against the correct key shares after a restart, even once the membership has changed.
+    key_indices: BTreeMap<N, u64>,
+    vote: Option<Change<N>>,
+    outputs: Vec<Batch<T, N>>,
+}
+
 /// A batch of transactions the algorithm has output.
+#[cfg_attr(feature = "serialization-serde", derive(Serialize, Deserialize))]
 #[derive(Clone)]
-pub struct Batch<T> {
+pub struct Batch<T, N> {
     pub epoch: u64,
     pub transactions: BTreeSet<T>,
+    /// The membership change committed in this epoch, if any. Callers apply it to spin connections
+    /// to joining nodes up and to leaving nodes down.
+    pub change: Option<Change<N>>,
+}
+
+/// A change to the set of participating nodes, voted for through the normal contribution mechanism
+/// and applied at a deterministic epoch boundary.
+///
+/// Unlike `Batch`/`Snapshot`, the serde derives here are unconditional rather than gated behind the
+/// `serialization-serde` feature: a change vote is embedded in every proposal's contribution, which
+/// is serialized with bincode as part of the core protocol regardless of that feature.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Change<N> {
+    /// Add the node with the given ID to the validator set.
+    Add(N),
+    /// Remove the node with the given ID from the validator set.
+    Remove(N),
 }
 
 /// A message sent to or received from another node's Honey Badger instance.
@@ -250,7 +845,8 @@ pub struct Batch<T> {
 pub enum Message<N> {
     /// A message belonging to the common subset algorithm in the given epoch.
     CommonSubset(u64, common_subset::Message<N>),
-    // TODO: Decryption share.
+    /// A decryption share for the ciphertext with the given index, agreed in the given epoch.
+    DecryptionShare(u64, usize, DecryptionShare),
 }
 
 /// A Honey Badger error.
@@ -258,8 +854,10 @@ pub enum Message<N> {
 pub enum Error {
     OwnIdMissing,
     UnknownSender,
+    BufferFull,
     CommonSubset(common_subset::Error),
     Bincode(Box<bincode::ErrorKind>),
+    Crypto(::crypto::Error),
 }
 
 impl From<common_subset::Error> for Error {
@@ -272,4 +870,227 @@ impl From<Box<bincode::ErrorKind>> for Error {
     fn from(err: Box<bincode::ErrorKind>) -> Error {
         Error::Bincode(err)
     }
-}
\ No newline at end of file
+}
+
+impl From<::crypto::Error> for Error {
+    fn from(err: ::crypto::Error) -> Error {
+        Error::Crypto(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::SecretKeySet;
+    use rand;
+
+    /// Deals a fresh threshold key set for `n` nodes tolerating `f` faults.
+    fn key_set(n: usize, f: usize) -> (PublicKeySet, Vec<SecretKeyShare>) {
+        let mut rng = rand::thread_rng();
+        let sk_set = SecretKeySet::random(f, &mut rng);
+        let pk_set = sk_set.public_keys();
+        let shares = (0..n).map(|i| sk_set.secret_key_share(i)).collect();
+        (pk_set, shares)
+    }
+
+    /// Ferries all pending messages between the given nodes until each of them has produced at least
+    /// `epochs` output batches, and returns the batches collected per node.
+    fn drive(
+        nodes: &mut [HoneyBadger<u64, u64>],
+        epochs: usize,
+    ) -> Vec<Vec<Batch<u64, u64>>> {
+        let mut outputs: Vec<Vec<Batch<u64, u64>>> = vec![Vec::new(); nodes.len()];
+        loop {
+            let mut queue: Vec<(u64, Target<u64>, Message<u64>)> = Vec::new();
+            for node in nodes.iter_mut() {
+                let sender = node.our_id().clone();
+                while let Some(tm) = node.next_message() {
+                    queue.push((sender, tm.target, tm.message));
+                }
+            }
+            if queue.is_empty() {
+                break;
+            }
+            for (sender, target, message) in queue {
+                for node in nodes.iter_mut() {
+                    let id = node.our_id().clone();
+                    if id == sender {
+                        continue;
+                    }
+                    let deliver = match target {
+                        Target::All => true,
+                        Target::Node(ref n) => *n == id,
+                    };
+                    if deliver {
+                        node.handle_message(&sender, message.clone())
+                            .expect("message handled");
+                    }
+                }
+            }
+            for (i, node) in nodes.iter_mut().enumerate() {
+                while let Some(batch) = node.next_output() {
+                    outputs[i].push(batch);
+                }
+            }
+            if outputs.iter().all(|o| o.len() >= epochs) {
+                break;
+            }
+        }
+        outputs
+    }
+
+    #[test]
+    fn decryption_round_reaches_agreement() {
+        let ids = vec![0u64, 1, 2, 3];
+        let (pk_set, shares) = key_set(4, 1);
+        let mut nodes: Vec<_> = ids.iter()
+            .enumerate()
+            .map(|(i, id)| {
+                HoneyBadger::new(
+                    *id,
+                    ids.clone(),
+                    pk_set.clone(),
+                    shares[i].clone(),
+                    4,
+                    100,
+                    vec![id * 10 + 1, id * 10 + 2],
+                ).expect("node")
+            })
+            .collect();
+        let outputs = drive(&mut nodes, 1);
+        let expected = &outputs[0][0].transactions;
+        assert!(!expected.is_empty());
+        for output in &outputs {
+            // All correct nodes decrypt the same batch for epoch 0.
+            assert_eq!(output[0].epoch, 0);
+            assert_eq!(&output[0].transactions, expected);
+        }
+    }
+
+    #[test]
+    fn membership_change_commits_at_same_epoch() {
+        let ids = vec![0u64, 1, 2, 3];
+        let (pk_set, shares) = key_set(4, 1);
+        let mut nodes: Vec<_> = ids.iter()
+            .enumerate()
+            .map(|(i, id)| {
+                HoneyBadger::new(
+                    *id,
+                    ids.clone(),
+                    pk_set.clone(),
+                    shares[i].clone(),
+                    4,
+                    100,
+                    vec![*id],
+                ).expect("node")
+            })
+            .collect();
+        // Every node votes to remove node 3.
+        for node in nodes.iter_mut() {
+            node.vote_for(Change::Remove(3));
+        }
+        let outputs = drive(&mut nodes, 3);
+        let committed: Vec<_> = outputs[0]
+            .iter()
+            .filter_map(|b| b.change.clone().map(|c| (b.epoch, c)))
+            .collect();
+        // The removal is committed exactly once, at a deterministic epoch boundary.
+        assert_eq!(committed.len(), 1);
+        assert_eq!(committed[0].1, Change::Remove(3));
+        // Every node commits the change at the same epoch.
+        for output in &outputs {
+            let node_committed: Vec<_> = output
+                .iter()
+                .filter_map(|b| b.change.clone().map(|c| (b.epoch, c)))
+                .collect();
+            assert_eq!(node_committed, committed);
+        }
+    }
+
+    /// Builds a single node for buffer-level unit tests.
+    fn single_node(batch_size: usize, capacity: usize, txs: Vec<u64>) -> HoneyBadger<u64, u64> {
+        let ids = vec![0u64, 1, 2, 3];
+        let (pk_set, shares) = key_set(4, 1);
+        HoneyBadger::new(0, ids, pk_set, shares[0].clone(), batch_size, capacity, txs)
+            .expect("node")
+    }
+
+    #[test]
+    fn buffer_deduplicates_and_bounds() {
+        let node = single_node(4, 100, vec![1, 1, 2, 2, 3]);
+        assert_eq!(node.buffer, vec![1, 2, 3]);
+
+        let mut node = single_node(4, 2, vec![1, 2]);
+        match node.add_transactions(vec![3]) {
+            Err(Error::BufferFull) => {}
+            other => panic!("expected BufferFull, got {:?}", other),
+        }
+        assert_eq!(node.buffer, vec![1, 2]);
+    }
+
+    #[cfg(feature = "serialization-serde")]
+    #[test]
+    fn snapshot_round_trip_preserves_state() {
+        let node = single_node(4, 100, vec![7, 8, 9]);
+        let snapshot = node.snapshot();
+        let bytes = bincode::serialize(&snapshot).expect("serialize");
+        let restored_snapshot: Snapshot<u64, u64> =
+            bincode::deserialize(&bytes).expect("deserialize");
+        let (pk_set, shares) = key_set(4, 1);
+        let restored =
+            HoneyBadger::from_snapshot(0, pk_set, shares[0].clone(), restored_snapshot)
+                .expect("restore");
+        assert_eq!(restored.epoch, node.epoch);
+        assert_eq!(restored.buffer, node.buffer);
+        assert_eq!(restored.key_indices, node.key_indices);
+    }
+
+    #[test]
+    fn observer_input_is_ignored() {
+        let ids = vec![0u64, 1, 2, 3];
+        let (pk_set, _) = key_set(4, 1);
+        let mut observer = HoneyBadger::<u64, u64>::new_observer(99, ids, pk_set, 4)
+            .expect("observer");
+        observer.add_transactions(vec![1, 2, 3]).expect("no-op");
+        assert!(observer.buffer.is_empty());
+        assert!(observer.next_message().is_none());
+    }
+
+    #[test]
+    fn observer_reconstructs_batches() {
+        let ids = vec![0u64, 1, 2, 3];
+        let (pk_set, shares) = key_set(4, 1);
+        let mut nodes: Vec<_> = ids.iter()
+            .enumerate()
+            .map(|(i, id)| {
+                HoneyBadger::new(
+                    *id,
+                    ids.clone(),
+                    pk_set.clone(),
+                    shares[i].clone(),
+                    4,
+                    100,
+                    vec![id * 10 + 1],
+                ).expect("node")
+            })
+            .collect();
+        nodes.push(
+            HoneyBadger::new_observer(99, ids.clone(), pk_set.clone(), 4).expect("observer"),
+        );
+        let outputs = drive(&mut nodes, 1);
+        // The observer rebuilds epoch 0's batch purely from the validators' shares.
+        let expected = &outputs[0][0].transactions;
+        assert_eq!(&outputs[4][0].transactions, expected);
+    }
+
+    #[test]
+    fn selection_always_includes_oldest() {
+        // batch_size 8 over 4 nodes leaves room for two transactions per proposal.
+        let node = single_node(8, 100, (1..=10).collect());
+        for _ in 0..32 {
+            let sample = node.select_transactions();
+            assert_eq!(sample.len(), 2);
+            assert!(sample.contains(&&1), "oldest transaction must always be proposed");
+        }
+    }
+}